@@ -81,6 +81,9 @@ impl From<Vec<Option<Publish>>> for OutgoingPublishBucket {
 #[derive(Debug, Clone)]
 pub struct PkidSet {
     set: FixedBitSet,
+    /// Next pkid `allocate` will try first, so allocations cycle through the
+    /// id space instead of always starting the search from 1.
+    next: u16,
 }
 
 impl PkidSet {
@@ -89,6 +92,7 @@ impl PkidSet {
     pub fn with_limit(max_pkid: u16) -> Self {
         Self {
             set: FixedBitSet::with_capacity(max_pkid as usize + 1),
+            next: 1,
         }
     }
 
@@ -144,4 +148,88 @@ impl PkidSet {
     pub fn clear(&mut self) {
         self.set.clear();
     }
+
+    /// Returns the underlying bitset storage as raw 32-bit blocks.
+    /// Intended for session persistence: storing these blocks is far more
+    /// compact than replaying every in-flight pkid individually.
+    pub fn blocks(&self) -> Vec<u32> {
+        self.set.as_slice().to_vec()
+    }
+
+    /// Rebuilds a `PkidSet` from blocks previously produced by `blocks`.
+    /// Returns `OutOfBounds` if `blocks` doesn't have exactly as many words
+    /// as `max_pkid` requires (bits rounded up to the word size), since that
+    /// means the blocks came from a set with a different capacity.
+    pub fn from_blocks(max_pkid: u16, blocks: Vec<u32>) -> Result<Self, OutOfBounds> {
+        let bits = max_pkid as usize + 1;
+        let expected_blocks = bits.div_ceil(u32::BITS as usize);
+        if blocks.len() != expected_blocks {
+            return Err(OutOfBounds(max_pkid));
+        }
+
+        Ok(Self {
+            set: FixedBitSet::with_capacity_and_blocks(bits, blocks),
+            next: 1,
+        })
+    }
+
+    /// Allocates the first free pkid, marking it used in the same call.
+    /// Pkid `0` is never handed out since it's invalid in MQTT. The search
+    /// resumes after the last pkid handed out and wraps around, so reuse is
+    /// spread across the id space instead of always favouring low pkids.
+    /// Returns `OutOfBounds(0)` if every pkid up to the configured limit is
+    /// already in use.
+    pub fn allocate(&mut self) -> Result<u16, OutOfBounds> {
+        let capacity = self.set.len();
+        if capacity <= 1 {
+            return Err(OutOfBounds(0));
+        }
+
+        let pkid = self
+            .first_clear_from(self.next as usize, capacity)
+            .or_else(|| self.first_clear_from(1, capacity))
+            .ok_or(OutOfBounds(0))?;
+
+        self.set.put(pkid);
+        self.next = if pkid + 1 < capacity { pkid as u16 + 1 } else { 1 };
+        Ok(pkid as u16)
+    }
+
+    /// Scans the bitset's blocks word-at-a-time for the first clear bit in
+    /// `from..capacity`. Fully-occupied words are skipped with a single
+    /// `==` check, and the first word actually inspected jumps straight to
+    /// `from`'s bit via `trailing_zeros` instead of walking the bits before
+    /// it one by one.
+    fn first_clear_from(&self, from: usize, capacity: usize) -> Option<usize> {
+        const BITS_PER_BLOCK: usize = u32::BITS as usize;
+
+        let blocks = self.set.as_slice();
+        let start_block = from / BITS_PER_BLOCK;
+
+        for (block_index, &block) in blocks.iter().enumerate().skip(start_block) {
+            let block_start = block_index * BITS_PER_BLOCK;
+            if block_start >= capacity {
+                break;
+            }
+
+            // Bits before `from` only matter in the first block we inspect;
+            // mask them off so `trailing_zeros` can't return them.
+            let skip = from.saturating_sub(block_start).min(BITS_PER_BLOCK);
+            let low_mask = if skip == BITS_PER_BLOCK {
+                u32::MAX
+            } else {
+                (1u32 << skip) - 1
+            };
+
+            let free = !block & !low_mask;
+            if free == 0 {
+                continue;
+            }
+
+            let pkid = block_start + free.trailing_zeros() as usize;
+            return if pkid < capacity { Some(pkid) } else { None };
+        }
+
+        None
+    }
 }
@@ -1,7 +1,58 @@
 use bytes::{Buf, BytesMut};
 use tokio_util::codec::{Decoder, Encoder};
 
-use super::{Error, Packet};
+use super::{Error, FixedHeader, Packet};
+
+/// Tracks how much of the next frame `Codec::decode` has already parsed, so a
+/// large packet split across many TCP reads doesn't re-parse its fixed header
+/// and remaining-length varint on every invocation.
+#[derive(Debug, Clone)]
+enum DecodeState {
+    /// Waiting for enough bytes to parse the fixed header.
+    FrameHeader,
+    /// Fixed header parsed; waiting for the rest of the frame to arrive.
+    Frame(FixedHeader),
+}
+
+/// Capabilities the broker advertised (typically via CONNACK properties)
+/// that the codec must honor when encoding outgoing packets.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BrokerCapabilities {
+    bits: u8,
+}
+
+impl BrokerCapabilities {
+    /// Broker doesn't want reason strings / user properties on ACKs and
+    /// DISCONNECT.
+    pub const SUPPRESS_PROBLEM_INFORMATION: Self = Self { bits: 0b001 };
+    /// Broker doesn't support retained messages.
+    pub const SUPPRESS_RETAIN: Self = Self { bits: 0b010 };
+    /// Broker doesn't support subscription identifiers.
+    pub const SUPPRESS_SUBSCRIPTION_IDS: Self = Self { bits: 0b100 };
+
+    /// No restrictions negotiated.
+    pub const fn empty() -> Self {
+        Self { bits: 0 }
+    }
+
+    pub fn insert(&mut self, other: Self) {
+        self.bits |= other.bits;
+    }
+
+    pub fn contains(&self, other: Self) -> bool {
+        self.bits & other.bits == other.bits
+    }
+}
+
+impl std::ops::BitOr for BrokerCapabilities {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self {
+            bits: self.bits | rhs.bits,
+        }
+    }
+}
 
 /// MQTT v4 codec
 #[derive(Debug, Clone)]
@@ -10,6 +61,70 @@ pub struct Codec {
     pub max_incoming_size: Option<usize>,
     /// Maximum packet size allowed by broker
     pub max_outgoing_size: Option<usize>,
+    /// Capabilities negotiated with the broker, honored on encode.
+    pub capabilities: BrokerCapabilities,
+    /// Partial-frame decode state, carried across `decode` calls.
+    decode_state: DecodeState,
+}
+
+impl Codec {
+    /// Creates a new `Codec` with the given incoming/outgoing size limits.
+    pub fn new(max_incoming_size: Option<usize>, max_outgoing_size: Option<usize>) -> Self {
+        Self {
+            max_incoming_size,
+            max_outgoing_size,
+            capabilities: BrokerCapabilities::empty(),
+            decode_state: DecodeState::FrameHeader,
+        }
+    }
+
+    /// Strips properties/fields the negotiated broker capabilities don't
+    /// support, so `encode` never emits frames the broker would reject.
+    fn apply_capabilities(&self, item: &mut Packet) {
+        if self.capabilities.contains(BrokerCapabilities::SUPPRESS_RETAIN) {
+            if let Packet::Publish(publish) = item {
+                publish.retain = false;
+            }
+        }
+
+        if self
+            .capabilities
+            .contains(BrokerCapabilities::SUPPRESS_SUBSCRIPTION_IDS)
+        {
+            if let Packet::Subscribe(subscribe) = item {
+                if let Some(properties) = &mut subscribe.properties {
+                    properties.id = None;
+                }
+            }
+        }
+
+        if self
+            .capabilities
+            .contains(BrokerCapabilities::SUPPRESS_PROBLEM_INFORMATION)
+        {
+            macro_rules! strip_problem_information {
+                ($properties:expr) => {
+                    if let Some(properties) = $properties {
+                        properties.reason_string = None;
+                        properties.user_properties.clear();
+                    }
+                };
+            }
+
+            match item {
+                Packet::PubAck(ack) => strip_problem_information!(&mut ack.properties),
+                Packet::PubRec(ack) => strip_problem_information!(&mut ack.properties),
+                Packet::PubRel(ack) => strip_problem_information!(&mut ack.properties),
+                Packet::PubComp(ack) => strip_problem_information!(&mut ack.properties),
+                Packet::SubAck(ack) => strip_problem_information!(&mut ack.properties),
+                Packet::UnsubAck(ack) => strip_problem_information!(&mut ack.properties),
+                Packet::Disconnect(disconnect) => {
+                    strip_problem_information!(&mut disconnect.properties)
+                }
+                _ => {}
+            }
+        }
+    }
 }
 
 impl Decoder for Codec {
@@ -17,20 +132,131 @@ impl Decoder for Codec {
     type Error = Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        if src.remaining() == 0 {
-            return Ok(None);
+        loop {
+            match self.decode_state.clone() {
+                DecodeState::FrameHeader => {
+                    let fixed_header = match parse_fixed_header(src, self.max_incoming_size)? {
+                        Some(fixed_header) => fixed_header,
+                        None => return Ok(None),
+                    };
+
+                    src.reserve(fixed_header.frame_length());
+                    self.decode_state = DecodeState::Frame(fixed_header);
+                }
+                DecodeState::Frame(fixed_header) => {
+                    if src.remaining() < fixed_header.frame_length() {
+                        return Ok(None);
+                    }
+
+                    let packet = Packet::read(src, self.max_incoming_size)?;
+                    self.decode_state = DecodeState::FrameHeader;
+                    return Ok(Some(packet));
+                }
+            }
         }
+    }
+}
+
+/// Parses just the fixed header (packet-type byte + remaining-length varint)
+/// from the front of `src`, without consuming any bytes. Unlike `check`,
+/// which only succeeds once the *whole* frame is buffered, this tells
+/// "header incomplete" (`Ok(None)`) apart from "header parsed, body still
+/// arriving", so `Codec::decode` can cache the header and stop re-parsing it
+/// on every partial read.
+///
+/// `max_incoming_size` is enforced here, before the caller reserves space for
+/// the frame, so a crafted remaining-length can't force an oversized
+/// up-front allocation.
+fn parse_fixed_header(
+    src: &BytesMut,
+    max_incoming_size: Option<usize>,
+) -> Result<Option<FixedHeader>, Error> {
+    if src.is_empty() {
+        return Ok(None);
+    }
+
+    let byte1 = src[0];
+    let mut remaining_len: usize = 0;
+
+    for len_len in 1..=4 {
+        let byte = match src.get(len_len) {
+            Some(&byte) => byte,
+            None => return Ok(None),
+        };
 
-        let packet = Packet::read(src, self.max_incoming_size)?;
-        Ok(Some(packet))
+        remaining_len |= ((byte & 0x7F) as usize) << (7 * (len_len - 1));
+
+        if byte & 0x80 == 0 {
+            let fixed_header = FixedHeader::new(byte1, len_len, remaining_len);
+
+            if let Some(max_size) = max_incoming_size {
+                if fixed_header.frame_length() > max_size {
+                    return Err(Error::PayloadSizeLimitExceeded(fixed_header.frame_length()));
+                }
+            }
+
+            return Ok(Some(fixed_header));
+        }
+    }
+
+    Err(Error::MalformedRemainingLength)
+}
+
+/// Guards a single packet's encode against `max_size`, rejecting it before
+/// `dst` grows rather than after the whole packet has been serialized.
+struct BoundedDst<'a> {
+    dst: &'a mut BytesMut,
+    start_len: usize,
+    max_size: usize,
+}
+
+impl<'a> BoundedDst<'a> {
+    fn new(dst: &'a mut BytesMut, max_size: usize) -> Self {
+        let start_len = dst.len();
+        Self {
+            dst,
+            start_len,
+            max_size,
+        }
+    }
+
+    /// Checks that a packet of `pkt_size` bytes fits within `max_size`,
+    /// reserving exactly the extra capacity `dst` will need if it does.
+    fn check_and_reserve(&mut self, pkt_size: usize) -> Result<(), Error> {
+        if pkt_size > self.max_size {
+            return Err(Error::OutgoingPacketTooLarge {
+                pkt_size,
+                max: self.max_size,
+            });
+        }
+
+        self.dst.reserve(pkt_size);
+        Ok(())
+    }
+
+    /// Drops anything written for this packet, restoring `dst` to its
+    /// pre-encode length.
+    fn rollback(&mut self) {
+        self.dst.truncate(self.start_len);
     }
 }
 
 impl Encoder<Packet> for Codec {
     type Error = Error;
 
-    fn encode(&mut self, item: Packet, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        item.write(dst, self.max_outgoing_size)?;
+    fn encode(&mut self, mut item: Packet, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.apply_capabilities(&mut item);
+
+        let max_size = self.max_outgoing_size.unwrap_or(usize::MAX);
+        let pkt_size = item.size();
+
+        let mut bounded = BoundedDst::new(dst, max_size);
+        bounded.check_and_reserve(pkt_size)?;
+
+        if let Err(e) = item.write(bounded.dst, self.max_outgoing_size) {
+            bounded.rollback();
+            return Err(e);
+        }
 
         Ok(())
     }
@@ -50,10 +276,7 @@ mod tests {
     #[test]
     fn outgoing_max_packet_size_check() {
         let mut buf = BytesMut::new();
-        let mut codec = Codec {
-            max_incoming_size: Some(100),
-            max_outgoing_size: Some(200),
-        };
+        let mut codec = Codec::new(Some(100), Some(200));
 
         let mut small_publish = Publish::new("hello/world", QoS::AtLeastOnce, vec![1; 100], None);
         small_publish.pkid = 1;